@@ -0,0 +1,587 @@
+//! Reading and writing transcripts tables in the formats produced (and
+//! consumed) by downstream tools: plain CSV and columnar Parquet.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use arrow::array::{Array, ArrowPrimitiveType, Float32Array, PrimitiveArray, StringArray, UInt32Array, UInt64Array};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::arrow::arrow_writer::ArrowWriter;
+use parquet::file::properties::WriterProperties;
+
+use crate::{Args, Transcript};
+
+/// On-disk format of a transcripts table.
+///
+/// The input format is auto-detected from `in_file`'s extension (a
+/// trailing `.gz` is ignored for detection purposes, so
+/// `transcripts.csv.gz` is still `Format::Csv`); the output format is
+/// chosen explicitly with `--format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Csv,
+    Parquet,
+}
+
+impl Format {
+    /// Guess the format of `path` from its extension, defaulting to CSV.
+    pub fn detect(path: &str) -> Format {
+        let path = path.strip_suffix(".gz").unwrap_or(path);
+        if path.ends_with(".parquet") {
+            Format::Parquet
+        } else {
+            Format::Csv
+        }
+    }
+}
+
+fn is_gzipped(path: &str) -> bool {
+    path.ends_with(".gz")
+}
+
+fn arrow_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("transcript_id", DataType::UInt64, false),
+        Field::new("cell_id", DataType::Utf8, false),
+        Field::new("overlaps_nucleus", DataType::UInt32, false),
+        Field::new("feature_name", DataType::Utf8, false),
+        Field::new("x_location", DataType::Float32, false),
+        Field::new("y_location", DataType::Float32, false),
+        Field::new("z_location", DataType::Float32, false),
+        Field::new("qv", DataType::Float32, false),
+        Field::new("fov_name", DataType::Utf8, false),
+        Field::new("nucleus_distance", DataType::Float32, false),
+    ])
+}
+
+/// Reads `Transcript` rows from a CSV or Parquet `in_file`.
+///
+/// Parquet files are read one record batch at a time through `arrow`'s
+/// `ParquetRecordBatchReader`, so a multi-hundred-million-row run never
+/// needs the whole table in memory.
+pub(crate) enum TranscriptReader {
+    Csv(Box<csv::DeserializeRecordsIntoIter<Box<dyn Read>, Transcript>>),
+    Parquet(Box<ParquetReaderState>),
+}
+
+/// The `Parquet` variant's payload, boxed so `TranscriptReader` stays
+/// small for the common `Csv` case instead of every instance paying for
+/// `ParquetRecordBatchReader`'s much larger size (clippy::large_enum_variant).
+pub(crate) struct ParquetReaderState {
+    batches: ParquetRecordBatchReader,
+    current: Option<(DecodedBatch, usize)>,
+}
+
+impl TranscriptReader {
+    pub(crate) fn open(path: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        match Format::detect(path) {
+            Format::Csv => {
+                let file = File::open(path)?;
+                let reader: Box<dyn Read> = if is_gzipped(path) {
+                    Box::new(MultiGzDecoder::new(file))
+                } else {
+                    Box::new(file)
+                };
+                let rdr = csv::Reader::from_reader(reader);
+                Ok(TranscriptReader::Csv(Box::new(rdr.into_deserialize())))
+            }
+            Format::Parquet => {
+                if is_gzipped(path) {
+                    return Err("gzip-compressed Parquet input is not supported \
+                                (Parquet already compresses its row groups internally)"
+                        .into());
+                }
+                let file = File::open(path)?;
+                let batches = ParquetRecordBatchReaderBuilder::try_new(file)?.build()?;
+                Ok(TranscriptReader::Parquet(Box::new(ParquetReaderState {
+                    batches,
+                    current: None,
+                })))
+            }
+        }
+    }
+}
+
+impl Iterator for TranscriptReader {
+    type Item = Result<Transcript, Box<dyn Error + Send + Sync>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            TranscriptReader::Csv(iter) => iter.next().map(|r| r.map_err(Into::into)),
+            TranscriptReader::Parquet(state) => loop {
+                if let Some((decoded, row)) = &mut state.current {
+                    if *row < decoded.num_rows() {
+                        let transcript = transcript_from_batch(decoded, *row);
+                        *row += 1;
+                        return Some(Ok(transcript));
+                    }
+                    state.current = None;
+                }
+
+                match state.batches.next() {
+                    Some(Ok(batch)) => match DecodedBatch::from_record_batch(&batch) {
+                        Ok(decoded) => state.current = Some((decoded, 0)),
+                        Err(err) => return Some(Err(err)),
+                    },
+                    Some(Err(err)) => return Some(Err(Box::new(err))),
+                    None => return None,
+                }
+            },
+        }
+    }
+}
+
+/// A Parquet record batch with every column cast to the exact Arrow type
+/// `transcript_from_batch` expects, computed once per batch instead of
+/// once per row.
+///
+/// Real Xenium `transcripts.parquet` files don't always use the exact
+/// integer width this tool works with internally (e.g. `transcript_id`
+/// as `Int64` rather than `UInt64`, `overlaps_nucleus` as `Int8` rather
+/// than `UInt32`), so columns are cast by logical type via
+/// `arrow::compute::cast` instead of downcast to one hard-coded Arrow
+/// type, which would reject those files as "malformed".
+#[derive(Debug)]
+pub(crate) struct DecodedBatch {
+    transcript_id: UInt64Array,
+    cell_id: StringArray,
+    overlaps_nucleus: UInt32Array,
+    feature_name: StringArray,
+    x_location: Float32Array,
+    y_location: Float32Array,
+    z_location: Float32Array,
+    qv: Float32Array,
+    fov_name: StringArray,
+    nucleus_distance: Float32Array,
+}
+
+impl DecodedBatch {
+    fn from_record_batch(batch: &RecordBatch) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(DecodedBatch {
+            transcript_id: cast_numeric_column(batch, "transcript_id", &DataType::UInt64)?,
+            cell_id: cast_utf8_column(batch, "cell_id")?,
+            overlaps_nucleus: cast_numeric_column(batch, "overlaps_nucleus", &DataType::UInt32)?,
+            feature_name: cast_utf8_column(batch, "feature_name")?,
+            x_location: cast_numeric_column(batch, "x_location", &DataType::Float32)?,
+            y_location: cast_numeric_column(batch, "y_location", &DataType::Float32)?,
+            z_location: cast_numeric_column(batch, "z_location", &DataType::Float32)?,
+            qv: cast_numeric_column(batch, "qv", &DataType::Float32)?,
+            fov_name: cast_utf8_column(batch, "fov_name")?,
+            nucleus_distance: cast_numeric_column(batch, "nucleus_distance", &DataType::Float32)?,
+        })
+    }
+
+    fn num_rows(&self) -> usize {
+        self.transcript_id.len()
+    }
+}
+
+/// Cast `name`'s column to `target` (e.g. `Int64` -> `UInt64`,
+/// `Int8` -> `UInt32`) rather than requiring it already be stored as
+/// `target`, so differently-typed Parquet writers still read cleanly.
+fn cast_numeric_column<T: ArrowPrimitiveType>(
+    batch: &RecordBatch,
+    name: &str,
+    target: &DataType,
+) -> Result<PrimitiveArray<T>, Box<dyn Error + Send + Sync>> {
+    let column = batch
+        .column_by_name(name)
+        .ok_or_else(|| format!("missing {name} column"))?;
+    if column.null_count() > 0 {
+        return Err(format!("column {name} contains nulls").into());
+    }
+    let cast_column = cast(column, target).map_err(|err| format!("malformed {name} column: {err}"))?;
+    Ok(cast_column
+        .as_any()
+        .downcast_ref::<PrimitiveArray<T>>()
+        .expect("cast() to the requested type always downcasts to it")
+        .clone())
+}
+
+fn cast_utf8_column(batch: &RecordBatch, name: &str) -> Result<StringArray, Box<dyn Error + Send + Sync>> {
+    let column = batch
+        .column_by_name(name)
+        .ok_or_else(|| format!("missing {name} column"))?;
+    if column.null_count() > 0 {
+        return Err(format!("column {name} contains nulls").into());
+    }
+    let cast_column =
+        cast(column, &DataType::Utf8).map_err(|err| format!("malformed {name} column: {err}"))?;
+    Ok(cast_column
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .expect("cast() to Utf8 always downcasts to StringArray")
+        .clone())
+}
+
+fn transcript_from_batch(batch: &DecodedBatch, row: usize) -> Transcript {
+    Transcript {
+        transcript_id: batch.transcript_id.value(row) as usize,
+        cell_id: batch.cell_id.value(row).to_string(),
+        overlaps_nucleus: batch.overlaps_nucleus.value(row) as usize,
+        feature_name: batch.feature_name.value(row).to_string(),
+        x_location: batch.x_location.value(row),
+        y_location: batch.y_location.value(row),
+        z_location: batch.z_location.value(row),
+        qv: batch.qv.value(row),
+        fov_name: batch.fov_name.value(row).to_string(),
+        nucleus_distance: batch.nucleus_distance.value(row),
+    }
+}
+
+/// The concrete sink behind a CSV `TranscriptWriter`.
+///
+/// Kept as an enum instead of a `Box<dyn Write>` so that `finish()` can
+/// call `GzEncoder::finish()` on the `Gz` variant: that's the method
+/// that actually writes the final deflate block and the CRC32/size
+/// trailer and returns a `Result`, unlike `Write::flush`, which a
+/// type-erased `Box<dyn Write>` would be limited to (and which
+/// `GzEncoder` only satisfies by finalizing silently, and fallibly, in
+/// `Drop`).
+pub(crate) enum CsvSink {
+    Plain(File),
+    Gz(GzEncoder<File>),
+}
+
+impl Write for CsvSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            CsvSink::Plain(f) => f.write(buf),
+            CsvSink::Gz(enc) => enc.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            CsvSink::Plain(f) => f.flush(),
+            CsvSink::Gz(enc) => enc.flush(),
+        }
+    }
+}
+
+/// Writes filtered `Transcript` rows out as CSV or Parquet, named after
+/// the same `X{..}_Y{..}_filtered_transcripts_nucleus_only_{..}` schema
+/// the tool has always used, with the extension matching `--format`.
+pub(crate) enum TranscriptWriter {
+    Csv(csv::Writer<CsvSink>),
+    Parquet {
+        writer: ArrowWriter<File>,
+        schema: Arc<Schema>,
+        rows: Vec<Transcript>,
+    },
+}
+
+/// Number of rows buffered before a Parquet row group is flushed.
+const PARQUET_ROW_GROUP_SIZE: usize = 100_000;
+
+impl TranscriptWriter {
+    pub(crate) fn create(args: &Args) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        std::fs::create_dir_all(&args.out_dir)?;
+
+        let extension = match args.format {
+            Format::Csv => "csv",
+            Format::Parquet => "parquet",
+        };
+        let mut outfile = format!(
+            "{}/X{}-{}_Y{}-{}_filtered_transcripts_nucleus_only_{}.{}",
+            args.out_dir,
+            args.min_x,
+            args.max_x,
+            args.min_y,
+            args.max_y,
+            args.nucleus_only,
+            extension
+        );
+
+        match args.format {
+            Format::Csv => {
+                if args.compress {
+                    outfile.push_str(".gz");
+                }
+                let file = File::create(outfile)?;
+                let sink = if args.compress {
+                    CsvSink::Gz(GzEncoder::new(file, Compression::default()))
+                } else {
+                    CsvSink::Plain(file)
+                };
+                Ok(TranscriptWriter::Csv(csv::Writer::from_writer(sink)))
+            }
+            Format::Parquet => {
+                if args.compress {
+                    return Err("--compress is not supported with --format parquet \
+                                (Parquet already compresses its row groups internally)"
+                        .into());
+                }
+                let schema = Arc::new(arrow_schema());
+                let file = File::create(outfile)?;
+                let writer =
+                    ArrowWriter::try_new(file, schema.clone(), Some(WriterProperties::builder().build()))?;
+                Ok(TranscriptWriter::Parquet {
+                    writer,
+                    schema,
+                    rows: Vec::with_capacity(PARQUET_ROW_GROUP_SIZE),
+                })
+            }
+        }
+    }
+
+    pub(crate) fn write(&mut self, record: &Transcript) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self {
+            TranscriptWriter::Csv(wtr) => wtr.serialize(record)?,
+            TranscriptWriter::Parquet { rows, .. } => {
+                rows.push(clone_transcript(record));
+                if rows.len() >= PARQUET_ROW_GROUP_SIZE {
+                    self.flush_row_group()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_row_group(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let TranscriptWriter::Parquet {
+            writer,
+            schema,
+            rows,
+        } = self
+        {
+            if rows.is_empty() {
+                return Ok(());
+            }
+            let batch = transcripts_to_batch(schema.clone(), rows)?;
+            writer.write(&batch)?;
+            rows.clear();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if matches!(self, TranscriptWriter::Parquet { .. }) {
+            self.flush_row_group()?;
+        }
+        match self {
+            TranscriptWriter::Csv(wtr) => {
+                // `into_inner()` flushes the `csv::Writer`'s own buffer and
+                // hands back the sink; for a gzipped sink that still isn't
+                // enough; only `GzEncoder::finish()` writes the final
+                // deflate block and the CRC32/size trailer, and it's the
+                // only one of the two that returns a `Result` we can
+                // propagate instead of losing to `Drop`.
+                let sink = wtr.into_inner().map_err(|err| err.to_string())?;
+                if let CsvSink::Gz(enc) = sink {
+                    enc.finish()?;
+                }
+            }
+            TranscriptWriter::Parquet { writer, .. } => {
+                writer.close()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn clone_transcript(t: &Transcript) -> Transcript {
+    Transcript {
+        transcript_id: t.transcript_id,
+        cell_id: t.cell_id.clone(),
+        overlaps_nucleus: t.overlaps_nucleus,
+        feature_name: t.feature_name.clone(),
+        x_location: t.x_location,
+        y_location: t.y_location,
+        z_location: t.z_location,
+        qv: t.qv,
+        fov_name: t.fov_name.clone(),
+        nucleus_distance: t.nucleus_distance,
+    }
+}
+
+fn transcripts_to_batch(
+    schema: Arc<Schema>,
+    rows: &[Transcript],
+) -> Result<RecordBatch, Box<dyn Error + Send + Sync>> {
+    let transcript_id: UInt64Array = rows.iter().map(|t| t.transcript_id as u64).collect();
+    let cell_id: StringArray = rows.iter().map(|t| Some(t.cell_id.as_str())).collect();
+    let overlaps_nucleus: UInt32Array = rows.iter().map(|t| t.overlaps_nucleus as u32).collect();
+    let feature_name: StringArray = rows.iter().map(|t| Some(t.feature_name.as_str())).collect();
+    let x_location: Float32Array = rows.iter().map(|t| t.x_location).collect();
+    let y_location: Float32Array = rows.iter().map(|t| t.y_location).collect();
+    let z_location: Float32Array = rows.iter().map(|t| t.z_location).collect();
+    let qv: Float32Array = rows.iter().map(|t| t.qv).collect();
+    let fov_name: StringArray = rows.iter().map(|t| Some(t.fov_name.as_str())).collect();
+    let nucleus_distance: Float32Array = rows.iter().map(|t| t.nucleus_distance).collect();
+
+    Ok(RecordBatch::try_new(
+        schema,
+        vec![
+            Arc::new(transcript_id),
+            Arc::new(cell_id),
+            Arc::new(overlaps_nucleus),
+            Arc::new(feature_name),
+            Arc::new(x_location),
+            Arc::new(y_location),
+            Arc::new(z_location),
+            Arc::new(qv),
+            Arc::new(fov_name),
+            Arc::new(nucleus_distance),
+        ],
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Args;
+
+    fn test_args(out_dir: &str, format: Format, compress: bool) -> Args {
+        Args {
+            in_file: "transcripts.csv".to_string(),
+            min_qv: 20.0,
+            min_x: 0.0,
+            max_x: 24000.0,
+            min_y: 0.0,
+            max_y: 24000.0,
+            nucleus_only: false,
+            out_dir: out_dir.to_string(),
+            format,
+            threads: None,
+            summary: None,
+            compress,
+            cell_id_map: None,
+        }
+    }
+
+    fn sample_transcripts() -> Vec<Transcript> {
+        vec![
+            Transcript {
+                transcript_id: 1,
+                cell_id: "aaaaaaaa-1".to_string(),
+                overlaps_nucleus: 1,
+                feature_name: "Gene1".to_string(),
+                x_location: 12.5,
+                y_location: 34.5,
+                z_location: 1.5,
+                qv: 40.0,
+                fov_name: "FOV1".to_string(),
+                nucleus_distance: 0.0,
+            },
+            Transcript {
+                transcript_id: 2,
+                cell_id: "0".to_string(),
+                overlaps_nucleus: 0,
+                feature_name: "BLANK_0001".to_string(),
+                x_location: 56.25,
+                y_location: 78.75,
+                z_location: 2.25,
+                qv: 10.0,
+                fov_name: "FOV2".to_string(),
+                nucleus_distance: 3.0,
+            },
+        ]
+    }
+
+    fn test_out_dir(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("xenium-filter-transcripts-io-test-{name}"))
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn parquet_round_trips_through_writer_and_reader() {
+        let out_dir = test_out_dir("parquet-round-trip");
+        let args = test_args(&out_dir, Format::Parquet, false);
+        let records = sample_transcripts();
+
+        let mut writer = TranscriptWriter::create(&args).unwrap();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let path =
+            format!("{out_dir}/X0-24000_Y0-24000_filtered_transcripts_nucleus_only_false.parquet");
+        let read_back: Vec<Transcript> = TranscriptReader::open(&path)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(read_back, records);
+        std::fs::remove_dir_all(&out_dir).ok();
+    }
+
+    #[test]
+    fn gzip_csv_round_trips_through_writer_and_reader() {
+        let out_dir = test_out_dir("gzip-csv-round-trip");
+        let args = test_args(&out_dir, Format::Csv, true);
+        let records = sample_transcripts();
+
+        let mut writer = TranscriptWriter::create(&args).unwrap();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let path =
+            format!("{out_dir}/X0-24000_Y0-24000_filtered_transcripts_nucleus_only_false.csv.gz");
+        assert_eq!(Format::detect(&path), Format::Csv);
+        let read_back: Vec<Transcript> = TranscriptReader::open(&path)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(read_back, records);
+        std::fs::remove_dir_all(&out_dir).ok();
+    }
+
+    #[test]
+    fn from_record_batch_rejects_nulls() {
+        // arrow_schema() declares every field non-nullable, but that only
+        // governs the *output* schema - an input Parquet file can still
+        // carry a null in, say, cell_id. Build a batch with exactly that
+        // (the schema here marks the column nullable only so
+        // RecordBatch::try_new accepts the null; our own writer never
+        // produces one).
+        let schema = Schema::new(vec![
+            Field::new("transcript_id", DataType::UInt64, false),
+            Field::new("cell_id", DataType::Utf8, true),
+            Field::new("overlaps_nucleus", DataType::UInt32, false),
+            Field::new("feature_name", DataType::Utf8, false),
+            Field::new("x_location", DataType::Float32, false),
+            Field::new("y_location", DataType::Float32, false),
+            Field::new("z_location", DataType::Float32, false),
+            Field::new("qv", DataType::Float32, false),
+            Field::new("fov_name", DataType::Utf8, false),
+            Field::new("nucleus_distance", DataType::Float32, false),
+        ]);
+
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(UInt64Array::from(vec![1])),
+                Arc::new(StringArray::from(vec![None::<&str>])),
+                Arc::new(UInt32Array::from(vec![0])),
+                Arc::new(StringArray::from(vec!["Gene1"])),
+                Arc::new(Float32Array::from(vec![1.0])),
+                Arc::new(Float32Array::from(vec![1.0])),
+                Arc::new(Float32Array::from(vec![1.0])),
+                Arc::new(Float32Array::from(vec![20.0])),
+                Arc::new(StringArray::from(vec!["FOV1"])),
+                Arc::new(Float32Array::from(vec![1.0])),
+            ],
+        )
+        .unwrap();
+
+        let err = DecodedBatch::from_record_batch(&batch).unwrap_err();
+        assert!(err.to_string().contains("cell_id"));
+        assert!(err.to_string().contains("null"));
+    }
+}
@@ -1,14 +1,27 @@
 use clap::Parser;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::{error::Error, fs::File};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc;
+use std::thread;
+
+mod io;
+mod summary;
+
+pub use io::Format;
+
+use summary::{RejectionCause, Summary};
 
 /// Filter transcripts from transcripts.csv based on Q-Score threshold
 /// and upper bounds on x and y coordinates. Remove negative controls.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
-    /// The path to the transcripts.csv file produced
-    /// by Xenium.
+    /// The path to the transcripts file produced by Xenium: `transcripts.csv`,
+    /// `transcripts.csv.gz`, or `transcripts.parquet`. The format is
+    /// detected from the file extension.
     in_file: String,
 
     /// The minimum Q-Score to pass filtering.
@@ -43,9 +56,35 @@ pub struct Args {
     ///   E.g.: X0-24000_Y0-24000_filtered_transcripts_nucleus_only_false.csv
     #[arg(long, default_value = ".", verbatim_doc_comment)]
     out_dir: String,
+
+    /// The format to write the filtered transcripts in.
+    #[arg(long, value_enum, default_value = "csv")]
+    format: Format,
+
+    /// Number of threads to use for filtering. Defaults to the number
+    /// of logical CPUs (see `rayon`'s default thread pool sizing).
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// Write a JSON QC summary (rows kept/filtered, rejection causes,
+    /// per-feature kept counts) to this path.
+    #[arg(long)]
+    summary: Option<String>,
+
+    /// Gzip-compress the output file, appending `.gz` to its name.
+    /// Only supported for `--format csv`.
+    #[arg(long, default_value_t = false)]
+    compress: bool,
+
+    /// Write a `cell_id,decoded_cell_id` table to this path, one row per
+    /// kept transcript with a cell assignment, so the integer cell_id
+    /// written to the filtered output can be joined back to the
+    /// original Xenium cell_id (`dataset_suffix` included).
+    #[arg(long)]
+    cell_id_map: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
 struct Transcript {
     transcript_id: usize,
     cell_id: String,
@@ -87,58 +126,156 @@ impl Transcript {
     }
 }
 
-pub fn run(args: Args) -> Result<(), Box<dyn Error>> {
-    let file = File::open(&args.in_file)?;
-    let mut rdr = csv::Reader::from_reader(file);
+/// Number of rows handed to rayon at a time, so filtering is chunked
+/// into batch-sized parallel tasks instead of one task per row.
+const BATCH_SIZE: usize = 10_000;
 
-    let mut wtr = create_out_file(&args)?;
+pub fn run(args: Args) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .ok();
+    }
 
-    for result in rdr.deserialize() {
-        let mut record: Transcript = result?;
+    let mut reader = io::TranscriptReader::open(&args.in_file)?;
+    let mut wtr = io::TranscriptWriter::create(&args)?;
 
-        // Check the coordinates and qv values
-        let keep = filter_transcripts(
-            &record,
-            args.min_x,
-            args.max_x,
-            args.min_y,
-            args.max_y,
-            args.min_qv,
-        );
-        if keep {
-            // remove cell assigment if not in nucleous and
-            // if arg nucleus_only
-            if args.nucleus_only && record.overlaps_nucleus == 0 {
-                record.cell_id = "0".to_string();
-            }
+    // A single writer thread keeps output ordering deterministic: each
+    // batch is filtered in parallel but serialized in the order it was
+    // read, and handed off through a bounded channel so the writer never
+    // falls far behind the readers.
+    let (tx, rx) = mpsc::sync_channel::<Vec<Transcript>>(4);
 
-            // Change cell_id of cell-free transcripts from UNASSIGNED to 0
-            if record.cell_id == "UNASSIGNED" {
-                record.cell_id = "0".to_string();
+    let writer = thread::spawn(move || -> Result<(), Box<dyn Error + Send + Sync>> {
+        for batch in rx {
+            for record in &batch {
+                wtr.write(record)?;
             }
+        }
+        wtr.finish()
+    });
+
+    let mut summary = Summary::default();
+    let mut cell_id_map = match &args.cell_id_map {
+        Some(path) => Some(CellIdMapWriter::create(path)?),
+        None => None,
+    };
+
+    loop {
+        let batch: Vec<Transcript> = (&mut reader).take(BATCH_SIZE).collect::<Result<_, _>>()?;
+        if batch.is_empty() {
+            break;
+        }
 
-            // Decode cell_ids
-            if record.cell_id != "0" {
-                record.cell_id = decode_cell_id(&record.cell_id).cell_id_prefix.to_string();
+        let outcomes: Vec<RowOutcome> = batch
+            .into_par_iter()
+            .map(|record| process_record(record, &args))
+            .collect();
+
+        // Folding the parallel results into the summary and the kept
+        // list happens serially, but it's pure bookkeeping compared to
+        // the filtering/decoding work above, so it doesn't erase the
+        // benefit of the parallel map.
+        let mut kept = Vec::with_capacity(outcomes.len());
+        for outcome in outcomes {
+            match outcome {
+                RowOutcome::Kept {
+                    record,
+                    nucleus_reassigned,
+                    cell_id_mapping,
+                } => {
+                    summary.record_kept(&record, nucleus_reassigned);
+                    if let (Some(map), Some((cell_id, decoded))) =
+                        (&mut cell_id_map, &cell_id_mapping)
+                    {
+                        map.write(cell_id, *decoded)?;
+                    }
+                    kept.push(record);
+                }
+                RowOutcome::Rejected(cause) => summary.record_rejected(cause),
             }
+        }
 
-            wtr.serialize(&record)?;
+        if tx.send(kept).is_err() {
+            break;
         }
     }
-    wtr.flush()?;
+    drop(tx);
+
+    match writer.join() {
+        Ok(result) => result?,
+        Err(_) => return Err("writer thread panicked".into()),
+    }
+
+    if let Some(path) = &args.summary {
+        summary.write(path)?;
+    }
+
+    if let Some(map) = cell_id_map {
+        map.finish()?;
+    }
+
     Ok(())
 }
 
-fn create_out_file(args: &Args) -> Result<csv::Writer<File>, Box<dyn Error>> {
-    std::fs::create_dir_all(&args.out_dir)?;
+enum RowOutcome {
+    Kept {
+        record: Transcript,
+        nucleus_reassigned: bool,
+        /// `(original cell_id, decoded cell_id_prefix)`, present when the
+        /// transcript is assigned to a cell (i.e. `cell_id` isn't `"0"`).
+        cell_id_mapping: Option<(String, usize)>,
+    },
+    Rejected(RejectionCause),
+}
+
+/// Apply the coordinate/QV/feature-name filter, the nucleus-only and
+/// UNASSIGNED cell-id rewrite, and `decode_cell_id` to a single record.
+///
+/// Pure function of `record` and `args`, so it's safe to call from many
+/// threads at once with no shared mutable state.
+fn process_record(mut record: Transcript, args: &Args) -> RowOutcome {
+    if let Some(cause) = classify_rejection(
+        &record,
+        args.min_x,
+        args.max_x,
+        args.min_y,
+        args.max_y,
+        args.min_qv,
+    ) {
+        return RowOutcome::Rejected(cause);
+    }
 
-    let outfile = format!(
-        "{}/X{}-{}_Y{}-{}_filtered_transcripts_nucleus_only_{}.csv",
-        args.out_dir, args.min_x, args.max_x, args.min_y, args.max_y, args.nucleus_only
-    );
+    // remove cell assigment if not in nucleous and
+    // if arg nucleus_only
+    let nucleus_reassigned = args.nucleus_only && record.overlaps_nucleus == 0;
+    if nucleus_reassigned {
+        record.cell_id = "0".to_string();
+    }
 
-    let wtr = csv::Writer::from_path(outfile)?;
-    Ok(wtr)
+    // Change cell_id of cell-free transcripts from UNASSIGNED to 0
+    if record.cell_id == "UNASSIGNED" {
+        record.cell_id = "0".to_string();
+    }
+
+    // Decode cell_ids
+    let mut cell_id_mapping = None;
+    if record.cell_id != "0" {
+        let original_cell_id = record.cell_id.clone();
+        let decoded_prefix = match decode_cell_id(&original_cell_id) {
+            Some(decoded) => decoded.cell_id_prefix,
+            None => return RowOutcome::Rejected(RejectionCause::MalformedCellId),
+        };
+        record.cell_id = decoded_prefix.to_string();
+        cell_id_mapping = Some((original_cell_id, decoded_prefix));
+    }
+
+    RowOutcome::Kept {
+        record,
+        nucleus_reassigned,
+        cell_id_mapping,
+    }
 }
 
 fn filter_transcripts(
@@ -149,13 +286,32 @@ fn filter_transcripts(
     max_y: f32,
     min_qc: f32,
 ) -> bool {
-    let keep = t.x_location >= min_x
-        && t.x_location <= max_x
-        && t.y_location >= min_y
-        && t.y_location <= max_y
-        && t.qv >= min_qc
-        && !exclude_feature_names(t);
-    return keep;
+    classify_rejection(t, min_x, max_x, min_y, max_y, min_qc).is_none()
+}
+
+/// Same checks as `filter_transcripts`, but reports *why* a record
+/// would be rejected instead of just whether it would be. The two stay
+/// in lock-step because `filter_transcripts` is defined in terms of
+/// this function.
+fn classify_rejection(
+    t: &Transcript,
+    min_x: f32,
+    max_x: f32,
+    min_y: f32,
+    max_y: f32,
+    min_qc: f32,
+) -> Option<RejectionCause> {
+    if t.x_location < min_x || t.x_location > max_x || t.y_location < min_y || t.y_location > max_y
+    {
+        return Some(RejectionCause::OutOfBounds);
+    }
+    if t.qv < min_qc {
+        return Some(RejectionCause::BelowMinQv);
+    }
+    if exclude_feature_names(t) {
+        return Some(RejectionCause::ExcludedFeature);
+    }
+    None
 }
 
 fn exclude_feature_names(t: &Transcript) -> bool {
@@ -165,35 +321,102 @@ fn exclude_feature_names(t: &Transcript) -> bool {
         || t.feature_name.starts_with("BLANK_");
 }
 
-fn decode_cell_id(cell_id: &str) -> CellID {
+/// Parse a `<shifted-hex>-<dataset_suffix>` `cell_id`, returning `None`
+/// instead of panicking if a row supplies a `cell_id` that doesn't
+/// match that shape (missing suffix, non-numeric suffix, or shifted-hex
+/// digits outside `a`..`p`) — malformed input from one row shouldn't be
+/// able to take down the whole rayon worker.
+fn decode_cell_id(cell_id: &str) -> Option<CellID> {
     let mut parts = cell_id.split("-");
-    let shifted_hex_digits = parts.next().unwrap();
-    let dataset_suffix: usize = parts.next().unwrap().parse().unwrap();
+    let shifted_hex_digits = parts.next()?;
+    let dataset_suffix: usize = parts.next()?.parse().ok()?;
 
-    let hex_array = shifted_hex_to_hex_array(shifted_hex_digits);
+    let hex_array = shifted_hex_to_hex_array(shifted_hex_digits)?;
 
-    let integer_value = convert_hex_array_to_int(&hex_array);
+    let cell_id_prefix = convert_hex_array_to_int(&hex_array)?;
 
-    CellID {
-        cell_id_prefix: integer_value,
-        dataset_suffix: dataset_suffix,
-    }
+    Some(CellID {
+        cell_id_prefix,
+        dataset_suffix,
+    })
 }
 
-fn shifted_hex_to_hex_array(shifted_hex_digits: &str) -> Vec<i32> {
-    let hex_digits: Vec<i32> = shifted_hex_digits
+/// `None` if any char falls outside `'a'..='p'`, the 16 letters Xenium
+/// shifts `0`..`F` into — an out-of-range char would otherwise fall
+/// through to `convert_hex_array_to_int` as a bogus (possibly negative)
+/// digit and silently corrupt the decoded prefix instead of being
+/// rejected as `MalformedCellId`.
+fn shifted_hex_to_hex_array(shifted_hex_digits: &str) -> Option<Vec<i32>> {
+    shifted_hex_digits
         .chars()
-        .map(|c| (c as i32) - ('a' as i32))
-        .collect();
-
-    hex_digits
+        .map(|c| {
+            let digit = (c as i32) - ('a' as i32);
+            if (0..=15).contains(&digit) {
+                Some(digit)
+            } else {
+                None
+            }
+        })
+        .collect()
 }
 
-fn convert_hex_array_to_int(hex_array: &[i32]) -> usize {
+fn convert_hex_array_to_int(hex_array: &[i32]) -> Option<usize> {
     let hex_string: String = hex_array.iter().map(|x| format!("{:X}", x)).collect();
 
-    let integer_value = usize::from_str_radix(&hex_string, 16).unwrap();
-    integer_value
+    usize::from_str_radix(&hex_string, 16).ok()
+}
+
+/// Number of hex digits Xenium pads the shifted-hex `cell_id` prefix to,
+/// e.g. `"ffkpbaba"` in `"ffkpbaba-1"`.
+const CELL_ID_HEX_WIDTH: usize = 8;
+
+/// Re-encode a `(cell_id_prefix, dataset_suffix)` pair decoded by
+/// `decode_cell_id` back into the original shifted-hex `cell_id`, e.g.
+/// `encode_cell_id(1437536272, 1) == "ffkpbaba-1"`.
+pub fn encode_cell_id(prefix: usize, suffix: usize) -> String {
+    let hex_array = int_to_hex_array(prefix);
+    let shifted_hex_digits = hex_array_to_shifted_hex(&hex_array);
+    format!("{}-{}", shifted_hex_digits, suffix)
+}
+
+fn int_to_hex_array(value: usize) -> Vec<i32> {
+    format!("{:0width$X}", value, width = CELL_ID_HEX_WIDTH)
+        .chars()
+        .map(|c| c.to_digit(16).unwrap() as i32)
+        .collect()
+}
+
+fn hex_array_to_shifted_hex(hex_array: &[i32]) -> String {
+    hex_array
+        .iter()
+        .map(|x| char::from_u32(('a' as i32 + x) as u32).unwrap())
+        .collect()
+}
+
+/// Writes the `--cell-id-map` table: one `cell_id,decoded_cell_id` row
+/// per kept transcript with a cell assignment, so downstream analyses
+/// can join the integer `cell_id` in the filtered output back to the
+/// original Xenium identifier without re-deriving `decode_cell_id`.
+struct CellIdMapWriter {
+    writer: BufWriter<File>,
+}
+
+impl CellIdMapWriter {
+    fn create(path: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "cell_id,decoded_cell_id")?;
+        Ok(CellIdMapWriter { writer })
+    }
+
+    fn write(&mut self, cell_id: &str, decoded: usize) -> Result<(), Box<dyn Error + Send + Sync>> {
+        writeln!(self.writer, "{},{}", cell_id, decoded)?;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.writer.flush()?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -306,9 +529,156 @@ mod tests {
     fn reverse_cell_id() {
         let cell_id = "ffkpbaba-1";
 
-        let decoded = decode_cell_id(cell_id);
+        let decoded = decode_cell_id(cell_id).unwrap();
 
         assert_eq!(decoded.dataset_suffix, 1);
         assert_eq!(decoded.cell_id_prefix, 1437536272);
     }
+
+    #[test]
+    fn encode_cell_id_round_trips_through_decode() {
+        for cell_id in ["ffkpbaba-1", "aaaaaaaa-0", "pppppppp-42"] {
+            let decoded = decode_cell_id(cell_id).unwrap();
+            let encoded = encode_cell_id(decoded.cell_id_prefix, decoded.dataset_suffix);
+            assert_eq!(encoded, cell_id);
+        }
+    }
+
+    #[test]
+    fn decode_cell_id_rejects_malformed_input() {
+        for cell_id in [
+            "ffkpbaba",
+            "ffkpbaba-not-a-number",
+            // '0' is outside the 'a'..='p' shifted-hex alphabet.
+            "0bkpbaba-1",
+        ] {
+            assert!(decode_cell_id(cell_id).is_none());
+        }
+    }
+
+    fn test_args() -> Args {
+        Args {
+            in_file: "transcripts.csv".to_string(),
+            min_qv: 20.0,
+            min_x: 0.0,
+            max_x: 24000.0,
+            min_y: 0.0,
+            max_y: 24000.0,
+            nucleus_only: false,
+            out_dir: ".".to_string(),
+            format: Format::Csv,
+            threads: None,
+            summary: None,
+            compress: false,
+            cell_id_map: None,
+        }
+    }
+
+    #[test]
+    fn process_record_filters_out_of_range() {
+        let mut t = Transcript::new();
+        t.x_location = 30000.0;
+
+        let args = test_args();
+        assert!(matches!(
+            process_record(t, &args),
+            RowOutcome::Rejected(RejectionCause::OutOfBounds)
+        ));
+    }
+
+    #[test]
+    fn process_record_rejects_malformed_cell_id() {
+        let mut t = Transcript::new();
+        t.cell_id = "not-a-shifted-hex-cell-id".to_string();
+
+        let args = test_args();
+        assert!(matches!(
+            process_record(t, &args),
+            RowOutcome::Rejected(RejectionCause::MalformedCellId)
+        ));
+    }
+
+    #[test]
+    fn process_record_nucleus_only_reassigns_cytoplasmic_transcripts() {
+        let mut t = Transcript::new();
+        t.cell_id = "aaaaaaaa-1".to_string();
+        t.overlaps_nucleus = 0;
+
+        let mut args = test_args();
+        args.nucleus_only = true;
+
+        match process_record(t, &args) {
+            RowOutcome::Kept {
+                record,
+                nucleus_reassigned,
+                ..
+            } => {
+                assert!(nucleus_reassigned);
+                assert_eq!(record.cell_id, "0");
+            }
+            RowOutcome::Rejected(_) => panic!("expected record to be kept"),
+        }
+    }
+
+    #[test]
+    fn process_record_decodes_cell_id() {
+        let mut t = Transcript::new();
+        t.cell_id = "ffkpbaba-1".to_string();
+
+        let args = test_args();
+        match process_record(t, &args) {
+            RowOutcome::Kept { record, .. } => assert_eq!(record.cell_id, "1437536272"),
+            RowOutcome::Rejected(_) => panic!("expected record to be kept"),
+        }
+    }
+
+    #[test]
+    fn process_record_reports_cell_id_mapping() {
+        let mut t = Transcript::new();
+        t.cell_id = "ffkpbaba-1".to_string();
+
+        let args = test_args();
+        match process_record(t, &args) {
+            RowOutcome::Kept {
+                cell_id_mapping, ..
+            } => {
+                assert_eq!(
+                    cell_id_mapping,
+                    Some(("ffkpbaba-1".to_string(), 1437536272))
+                );
+            }
+            RowOutcome::Rejected(_) => panic!("expected record to be kept"),
+        }
+    }
+
+    #[test]
+    fn run_writes_cell_id_map_file() {
+        let dir = std::env::temp_dir().join("xenium-filter-transcripts-lib-test-cell-id-map");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let in_file = dir.join("transcripts.csv");
+        std::fs::write(
+            &in_file,
+            "transcript_id,cell_id,overlaps_nucleus,feature_name,x_location,y_location,z_location,qv,fov_name,nucleus_distance\n\
+             1,ffkpbaba-1,1,Gene1,100.0,100.0,100.0,25.0,FOV1,1.0\n\
+             2,0,0,Gene2,100.0,100.0,100.0,25.0,FOV1,1.0\n",
+        )
+        .unwrap();
+
+        let map_path = dir.join("cell_id_map.csv");
+        let mut args = test_args();
+        args.in_file = in_file.to_str().unwrap().to_string();
+        args.out_dir = dir.to_str().unwrap().to_string();
+        args.cell_id_map = Some(map_path.to_str().unwrap().to_string());
+
+        run(args).unwrap();
+
+        // Only the first row has a cell assignment ("0" means unassigned
+        // and is never written to the map), so the file should have the
+        // header and exactly that one row.
+        let contents = std::fs::read_to_string(&map_path).unwrap();
+        assert_eq!(contents, "cell_id,decoded_cell_id\nffkpbaba-1,1437536272\n");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }
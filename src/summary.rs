@@ -0,0 +1,179 @@
+//! Machine-readable QC report written alongside the filtered output
+//! when `--summary` is given, so a run's filtering decisions don't have
+//! to be reconstructed by re-reading the output file.
+
+use serde::ser::SerializeMap;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+
+use crate::Transcript;
+
+/// Why a single transcript was dropped during filtering.
+pub enum RejectionCause {
+    /// `x_location`/`y_location` fell outside `min_x..max_x`/`min_y..max_y`.
+    OutOfBounds,
+    /// `qv` was below `--min-qv`.
+    BelowMinQv,
+    /// `feature_name` matched a negative-control/antisense/BLANK prefix.
+    ExcludedFeature,
+    /// `cell_id` wasn't `"0"`/`"UNASSIGNED"` but didn't parse as
+    /// `<shifted-hex>-<dataset_suffix>`, so `decode_cell_id` couldn't
+    /// assign it to a cell.
+    MalformedCellId,
+}
+
+/// Breakdown of why rejected rows were rejected.
+#[derive(Debug, Default, Serialize)]
+pub struct Rejections {
+    pub out_of_bounds: usize,
+    pub below_min_qv: usize,
+    pub excluded_feature: usize,
+    pub malformed_cell_id: usize,
+}
+
+/// Counts accumulated over a run of `run()`, written out as JSON.
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub total_rows: usize,
+    pub kept_rows: usize,
+    pub filtered_rows: usize,
+    pub rejections: Rejections,
+    pub nucleus_only_reassigned: usize,
+    /// Kept-transcript counts per `feature_name`, in first-seen order,
+    /// so the report is diff-stable across runs over the same input.
+    pub feature_counts: FeatureCounts,
+}
+
+/// Insertion-ordered `feature_name -> count` table.
+///
+/// A plain `serde_json::Map` only preserves insertion order when
+/// `serde_json` is built with its `preserve_order` feature; otherwise
+/// it's a sorted `BTreeMap`. `FeatureCounts` tracks its own order and
+/// serializes as a JSON object in that order regardless of how its
+/// dependencies are configured.
+///
+/// Lookups go through `counts` (a `HashMap`), not a linear scan over
+/// `order`, so `increment` stays O(1) amortized even for a gene panel
+/// of thousands of features hit by hundreds of millions of kept rows.
+#[derive(Debug, Default)]
+pub struct FeatureCounts {
+    order: Vec<String>,
+    counts: HashMap<String, u64>,
+}
+
+impl FeatureCounts {
+    fn increment(&mut self, feature_name: &str) {
+        if let Some(count) = self.counts.get_mut(feature_name) {
+            *count += 1;
+        } else {
+            self.order.push(feature_name.to_string());
+            self.counts.insert(feature_name.to_string(), 1);
+        }
+    }
+}
+
+impl Serialize for FeatureCounts {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(self.order.len()))?;
+        for feature_name in &self.order {
+            map.serialize_entry(feature_name, &self.counts[feature_name])?;
+        }
+        map.end()
+    }
+}
+
+impl Summary {
+    pub fn record_kept(&mut self, t: &Transcript, nucleus_reassigned: bool) {
+        self.total_rows += 1;
+        self.kept_rows += 1;
+        if nucleus_reassigned {
+            self.nucleus_only_reassigned += 1;
+        }
+
+        self.feature_counts.increment(&t.feature_name);
+    }
+
+    pub fn record_rejected(&mut self, cause: RejectionCause) {
+        self.total_rows += 1;
+        self.filtered_rows += 1;
+        match cause {
+            RejectionCause::OutOfBounds => self.rejections.out_of_bounds += 1,
+            RejectionCause::BelowMinQv => self.rejections.below_min_qv += 1,
+            RejectionCause::ExcludedFeature => self.rejections.excluded_feature += 1,
+            RejectionCause::MalformedCellId => self.rejections.malformed_cell_id += 1,
+        }
+    }
+
+    pub fn write(&self, path: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Transcript;
+
+    fn transcript(feature_name: &str) -> Transcript {
+        let mut t = Transcript::new();
+        t.feature_name = feature_name.to_string();
+        t
+    }
+
+    #[test]
+    fn record_rejected_tallies_by_cause() {
+        let mut summary = Summary::default();
+        summary.record_rejected(RejectionCause::OutOfBounds);
+        summary.record_rejected(RejectionCause::OutOfBounds);
+        summary.record_rejected(RejectionCause::BelowMinQv);
+        summary.record_rejected(RejectionCause::ExcludedFeature);
+        summary.record_rejected(RejectionCause::MalformedCellId);
+
+        assert_eq!(summary.total_rows, 5);
+        assert_eq!(summary.filtered_rows, 5);
+        assert_eq!(summary.rejections.out_of_bounds, 2);
+        assert_eq!(summary.rejections.below_min_qv, 1);
+        assert_eq!(summary.rejections.excluded_feature, 1);
+        assert_eq!(summary.rejections.malformed_cell_id, 1);
+    }
+
+    #[test]
+    fn record_kept_counts_nucleus_only_reassignments() {
+        let mut summary = Summary::default();
+        summary.record_kept(&transcript("Gene1"), true);
+        summary.record_kept(&transcript("Gene2"), false);
+
+        assert_eq!(summary.total_rows, 2);
+        assert_eq!(summary.kept_rows, 2);
+        assert_eq!(summary.nucleus_only_reassigned, 1);
+    }
+
+    #[test]
+    fn feature_counts_serialize_in_first_seen_order() {
+        let mut summary = Summary::default();
+        // "Zyx" is fed in first and repeated, so a sorted (e.g. BTreeMap)
+        // serialization would put it after "Abc"/"Mno" - this only passes
+        // if FeatureCounts preserves insertion order.
+        summary.record_kept(&transcript("Zyx"), false);
+        summary.record_kept(&transcript("Abc"), false);
+        summary.record_kept(&transcript("Zyx"), false);
+        summary.record_kept(&transcript("Mno"), false);
+
+        let json = serde_json::to_string(&summary).unwrap();
+        let zyx = json.find("\"Zyx\"").unwrap();
+        let abc = json.find("\"Abc\"").unwrap();
+        let mno = json.find("\"Mno\"").unwrap();
+        assert!(zyx < abc);
+        assert!(abc < mno);
+        assert!(json.contains("\"Zyx\":2"));
+        assert!(json.contains("\"Abc\":1"));
+        assert!(json.contains("\"Mno\":1"));
+    }
+}